@@ -7,11 +7,15 @@ extern crate libc;
 
 // std imports
 use std::error::Error;
-use std::ffi::CString;
-use std::fs::Metadata;
+use std::ffi::{CString, OsStr};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
 use std::os::linux::fs::MetadataExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::PathBuf;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 /// Filter a list of files by properties. `stest` takes a list of files and
 /// filters by the files' properties, analogous to `test`(1).  Files which pass
@@ -52,6 +56,14 @@ pub struct Opt {
     #[structopt(short = "l")]
     dir_contents: bool,
 
+    /// Recurse into subdirectories when testing directory contents
+    #[structopt(short = "R")]
+    recursive: bool,
+
+    /// Maximum depth to recurse to when `-R` is given
+    #[structopt(long = "max-depth")]
+    max_depth: Option<usize>,
+
     /// Test that files are newer than file
     #[structopt(short = "n", parse(from_os_str))]
     newer_than: Option<PathBuf>,
@@ -92,6 +104,11 @@ pub struct Opt {
     #[structopt(short = "x")]
     executable: bool,
 
+    /// Use NUL instead of newline as the record separator on stdin and
+    /// stdout, for file names that may contain newlines
+    #[structopt(short = "0", long = "null")]
+    null_data: bool,
+
     /// List of files
     #[structopt(parse(from_os_str))]
     files: Vec<PathBuf>,
@@ -134,102 +151,234 @@ impl<'t> Stest<'t> {
         }
     }
 
-    /// Take input from stdin - currently not supported.
+    /// Take input from stdin, one file name per record, separated by NUL
+    /// (`-0`) or newline. Read as raw bytes rather than `BufRead::lines()`
+    /// so file names that are not valid UTF-8 aren't silently dropped.
     fn run_stdin(&self) -> bool {
-        panic!("Read from stdin");
+        let mut buf = Vec::new();
+
+        if io::stdin().lock().read_to_end(&mut buf).is_err() {
+            return false;
+        }
+
+        let sep = if self.opt.null_data { 0u8 } else { b'\n' };
+
+        buf.split(|&b| b == sep)
+            .filter(|record| !record.is_empty())
+            .map(|record| self.process(&PathBuf::from(OsStr::from_bytes(record))))
+            .fold(false, reduce)
     }
 
     /// Take input from the files passed in the options.
     fn run_opts(&self) -> bool {
-        let iter = self.opt.files.iter();
+        self.opt
+            .files
+            .iter()
+            .map(|path| self.process(path))
+            .fold(false, reduce)
+    }
 
+    /// Test a single path, honouring the `-l` directory contents mode.
+    fn process(&self, path: &PathBuf) -> bool {
         if self.opt.dir_contents {
-            iter.map(|path| self.test_dir(&path)).fold(false, reduce)
+            self.test_dir(path)
         } else {
-            iter.map(|path| {
-                path.to_str()
-                    .and_then(|file_name| Some(self.test(&path, file_name)))
-                    .unwrap_or(false)
-            }).fold(false, reduce)
+            self.test(path, path.as_os_str())
         }
     }
 
     /// Test the contents of a directory.
+    ///
+    /// The directory is opened once and each entry is tested relative to
+    /// that open file descriptor via `fstatat`/`faccessat`, rather than
+    /// re-resolving the full path for every entry, so the path is not walked
+    /// again from the root. Each entry is still looked up by name after it
+    /// was enumerated, so an entry renamed or replaced in the meantime is
+    /// not the one that gets tested.
     fn test_dir(&self, dir_path: &PathBuf) -> bool {
-        if let Ok(dir) = dir_path.read_dir() {
-            let dir_contents = dir.filter_map(|path_result| {
-                path_result.ok().and_then(|path| Some(path.path()))
-            });
-
-            return dir_contents
-                .map(|path| {
-                    path.file_name()
-                        .and_then(|os_str| os_str.to_str())
-                        .and_then(|file_name| Some(self.test(&path, file_name)))
-                        .unwrap_or(false)
-                })
-                .fold(false, reduce);
+        if self.opt.recursive {
+            return self.test_dir_recursive(dir_path);
         }
 
-        false
+        let dir = match dir_path.read_dir() {
+            Ok(dir) => dir,
+            Err(_) => return false,
+        };
+        let handle = match File::open(dir_path) {
+            Ok(handle) => handle,
+            Err(_) => return false,
+        };
+        let dirfd = handle.as_raw_fd();
+
+        dir.filter_map(|entry| entry.ok())
+            .map(|entry| self.test_entry(dirfd, &entry.file_name()))
+            .fold(false, reduce)
     }
 
-    /// Test the provided file.
-    fn test(&self, path: &PathBuf, file_name: &str) -> bool {
-
-        let file = path.metadata();
-        let c_path = path.to_str().and_then(|path| CString::new(path).ok());
-
-        // The test outcome.
+    /// Walk a directory tree depth-first, testing every entry at every level.
+    ///
+    /// A stack of `(ReadDir, File, depth)` tracks the directories still
+    /// being visited: the `File` keeps that directory's file descriptor
+    /// open so entries can be tested relative to it. Symlinked directories
+    /// are not descended into unless `-h` is also given, to avoid following
+    /// cycles.
+    fn test_dir_recursive(&self, dir_path: &PathBuf) -> bool {
         let mut result = false;
+        let mut stack = Vec::new();
+
+        if let (Ok(dir), Ok(handle)) = (dir_path.read_dir(), File::open(dir_path)) {
+            stack.push((dir, handle, 0));
+        }
 
-        // Check if file is accessible.
-        if file.is_ok() && c_path.is_some() {
-            let file = file.unwrap();
-            let c_path = c_path.unwrap();
-
-            // If file is accessible test it.
-            result = (self.opt.hidden || !file_name.starts_with('.')) &&
-                (!self.opt.block_special || s_isval(libc::S_IFBLK, &file)) &&
-                (!self.opt.char_special || s_isval(libc::S_IFCHR, &file)) &&
-                (!self.opt.directory || file.is_dir()) &&
-                (!self.opt.regular || file.is_file()) &&
-                (!self.opt.set_gid_set || s_isset(libc::S_ISGID, &file)) &&
-                (!self.opt.symbolic_link || is_symlink(path)) &&
-                (!self.opt.newer_than.is_some() ||
-                     (self.compare.unwrap() < file.modified().unwrap())) &&
-                (!self.opt.older_than.is_some() ||
-                     (file.modified().unwrap() < self.compare.unwrap())) &&
-                (!self.opt.fifo || s_isval(libc::S_IFIFO, &file)) &&
-                (!self.opt.readable || access(libc::R_OK, &c_path)) &&
-                (!self.opt.not_empty || (file.len() > 0)) &&
-                (!self.opt.set_gid_set || s_isset(libc::S_ISUID, &file)) &&
-                (!self.opt.writable || access(libc::W_OK, &c_path)) &&
-                (!self.opt.executable || access(libc::X_OK, &c_path));
+        while let Some((mut dir, handle, depth)) = stack.pop() {
+            let dirfd = handle.as_raw_fd();
+
+            while let Some(entry) = dir.next() {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(_) => continue,
+                };
+                let file_name = entry.file_name();
+                let file_type = entry.file_type().ok();
+                let is_symlink = file_type.map(|t| t.is_symlink()).unwrap_or(false);
+
+                result = self.test_entry(dirfd, &file_name) || result;
+
+                let within_depth = self.opt.max_depth.map_or(true, |max| depth < max);
+
+                // A symlink's `file_type()` (from the directory entry
+                // itself) never reports `is_dir()`, even when it points at
+                // a directory, so the target has to be stat-ed through the
+                // link to tell whether there is anything to descend into.
+                let leads_to_dir = if is_symlink {
+                    entry.path().metadata().map(|m| m.is_dir()).unwrap_or(false)
+                } else {
+                    file_type.map(|t| t.is_dir()).unwrap_or(false)
+                };
+
+                if leads_to_dir && within_depth && (!is_symlink || self.opt.symbolic_link) {
+                    let child_path = entry.path();
+                    if let (Ok(child_dir), Ok(child_handle)) =
+                        (child_path.read_dir(), File::open(&child_path))
+                    {
+                        stack.push((dir, handle, depth));
+                        stack.push((child_dir, child_handle, depth + 1));
+                        break;
+                    }
+                }
+            }
         }
 
-        // Invert result if necessary.
-        result ^= self.opt.invert;
+        result
+    }
+
+    /// Test the provided file, resolved from the current working directory.
+    fn test(&self, path: &PathBuf, file_name: &OsStr) -> bool {
+        let result = match (path.metadata(), CString::new(path.as_os_str().as_bytes())) {
+            (Ok(file), Ok(c_path)) => self.matches(
+                file_name,
+                file.st_mode(),
+                file.len(),
+                file.modified().unwrap(),
+                is_symlink(path),
+                |mode| access(mode, &c_path),
+            ),
+            _ => false,
+        };
+
+        self.finish(file_name, result)
+    }
+
+    /// Test a directory entry relative to its directory's file descriptor,
+    /// via `fstatat`/`faccessat` rather than re-resolving its full path.
+    ///
+    /// The entry is first `lstat`-ed to determine whether it is itself a
+    /// symlink (for `-h`) without resolving it. All other property tests
+    /// (type, size, mtime) follow the link to its target, matching `test()`
+    /// and the semantics a file's properties are expected to have.
+    fn test_entry(&self, dirfd: RawFd, file_name: &OsStr) -> bool {
+        let result = CString::new(file_name.as_bytes())
+            .ok()
+            .and_then(|c_name| stat_at(dirfd, &c_name, true).map(|lstat| (c_name, lstat)))
+            .and_then(|(c_name, lstat)| {
+                let is_symlink = s_isval(libc::S_IFLNK, lstat.st_mode);
+
+                let stat = if is_symlink {
+                    stat_at(dirfd, &c_name, false)?
+                } else {
+                    lstat
+                };
+
+                Some(self.matches(
+                    file_name,
+                    stat.st_mode,
+                    stat.st_size as u64,
+                    modified_time(&stat),
+                    is_symlink,
+                    |mode| access_at(dirfd, &c_name, mode),
+                ))
+            })
+            .unwrap_or(false);
+
+        self.finish(file_name, result)
+    }
+
+    /// Evaluate the configured property tests against a file's attributes.
+    fn matches(
+        &self,
+        file_name: &OsStr,
+        st_mode: u32,
+        size: u64,
+        modified: SystemTime,
+        is_symlink: bool,
+        can_access: impl Fn(i32) -> bool,
+    ) -> bool {
+        (self.opt.hidden || file_name.as_bytes().first() != Some(&b'.')) &&
+            (!self.opt.block_special || s_isval(libc::S_IFBLK, st_mode)) &&
+            (!self.opt.char_special || s_isval(libc::S_IFCHR, st_mode)) &&
+            (!self.opt.directory || s_isval(libc::S_IFDIR, st_mode)) &&
+            (!self.opt.regular || s_isval(libc::S_IFREG, st_mode)) &&
+            (!self.opt.set_gid_set || s_isset(libc::S_ISGID, st_mode)) &&
+            (!self.opt.symbolic_link || is_symlink) &&
+            (!self.opt.newer_than.is_some() ||
+                 (self.compare.unwrap() < modified)) &&
+            (!self.opt.older_than.is_some() ||
+                 (modified < self.compare.unwrap())) &&
+            (!self.opt.fifo || s_isval(libc::S_IFIFO, st_mode)) &&
+            (!self.opt.readable || can_access(libc::R_OK)) &&
+            (!self.opt.not_empty || (size > 0)) &&
+            (!self.opt.set_uid_set || s_isset(libc::S_ISUID, st_mode)) &&
+            (!self.opt.writable || can_access(libc::W_OK)) &&
+            (!self.opt.executable || can_access(libc::X_OK))
+    }
+
+    /// Apply `-v` and print the file name unless `-q` was given. Written as
+    /// raw bytes rather than through `println!` so that file names which
+    /// are not valid UTF-8 round-trip intact.
+    fn finish(&self, file_name: &OsStr, result: bool) -> bool {
+        let result = result ^ self.opt.invert;
 
-        // Print successful result unless asked not to.
         if result && !self.opt.quiet {
-            println!("{}", file_name);
+            let sep: &[u8] = if self.opt.null_data { b"\0" } else { b"\n" };
+            let stdout = io::stdout();
+            let mut handle = stdout.lock();
+            let _ = handle.write_all(file_name.as_bytes());
+            let _ = handle.write_all(sep);
         }
 
         result
-
     }
 }
 
 /// Utility function to provide the function of the libc macros such as ISBLK,
 /// ISCHR, ISFIFO.
-fn s_isval(s_ifval: u32, file: &Metadata) -> bool {
-    (file.st_mode() & libc::S_IFMT) == s_ifval
+fn s_isval(s_ifval: u32, mode: u32) -> bool {
+    (mode & libc::S_IFMT) == s_ifval
 }
 
 /// Utility function to check the flags if the file's mode.
-fn s_isset(s_isflg: i32, file: &Metadata) -> bool {
-    (file.st_mode() & s_isflg as u32) != 0
+fn s_isset(s_isflg: i32, mode: u32) -> bool {
+    (mode & s_isflg as u32) != 0
 }
 
 /// Utility function to check if file at path is a symlink.
@@ -240,7 +389,47 @@ fn is_symlink(path: &PathBuf) -> bool {
         .is_some()
 }
 
-/// Wrapper around libc's unsafe access call.
+/// `fstatat(2)` a directory entry by name, relative to `dirfd`. When
+/// `nofollow` is set, symlinks are not resolved (`AT_SYMLINK_NOFOLLOW`), so
+/// the result reflects the entry that was actually enumerated rather than
+/// whatever it may point to.
+fn stat_at(dirfd: RawFd, c_name: &CString, nofollow: bool) -> Option<libc::stat> {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    let flags = if nofollow { libc::AT_SYMLINK_NOFOLLOW } else { 0 };
+    let rc = unsafe { libc::fstatat(dirfd, c_name.as_ptr(), &mut stat, flags) };
+
+    if rc == 0 {
+        Some(stat)
+    } else {
+        None
+    }
+}
+
+/// Convert a `stat(2)` modification time into a `SystemTime`.
+fn modified_time(stat: &libc::stat) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::new(stat.st_mtime as u64, stat.st_mtime_nsec as u32)
+}
+
+/// Wrapper around libc's access check.
+///
+/// Uses `faccessat(2)` with `AT_EACCESS` so permissions are checked against
+/// the *effective* UID/GID rather than the real one, matching the
+/// expectations of a setuid or sudo-run menu program. The name is resolved
+/// again by this call, separately from the earlier `metadata()`/`fstatat()`.
+/// Only Linux and Android use `faccessat`; all other platforms use plain
+/// `access(2)`, in which case `dirfd` is ignored.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn access_at(dirfd: RawFd, c_name: &CString, rwx: i32) -> bool {
+    (unsafe { libc::faccessat(dirfd, c_name.as_ptr(), rwx, libc::AT_EACCESS) } == 0)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn access_at(_dirfd: RawFd, c_name: &CString, rwx: i32) -> bool {
+    (unsafe { libc::access(c_name.as_ptr(), rwx) } == 0)
+}
+
+/// `access_at` rooted at the current working directory, for paths that are
+/// not relative to an already-open directory.
 fn access(rwx: i32, c_path: &CString) -> bool {
-    (unsafe { libc::access(c_path.as_ptr(), rwx) } == 0)
+    access_at(libc::AT_FDCWD, c_path, rwx)
 }